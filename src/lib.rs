@@ -10,11 +10,20 @@
 
 #![crate_name = "zonememstat"]
 
+use std::fmt;
+use std::pin::Pin;
+
+use nom::branch::alt;
+use nom::bytes::complete::take_while1;
+use nom::character::complete::{char, digit1, space1};
+use nom::combinator::{map, map_res};
+use nom::IResult;
 use serde::Serialize;
 
 use tokio_process_stream::ProcessLineStream;
 use tokio::process::Command;
-use tokio_stream::StreamExt;
+use tokio_stream::{Stream, StreamExt};
+use zonename::{getzoneid, getzoneidbyname, getzonenamebyid};
 
 /// The global zone swap usage is not calculated by zonememstat, but it still
 /// may be useful to be able to get allocated RSS and max memory for the global
@@ -43,6 +52,10 @@ pub enum Alias {
 pub struct ZoneMemStat {
     /// The zone name. This will be a uuid.
     pub zonename: String,
+    /// The numeric zone ID, resolved from `zonename` via
+    /// `getzoneidbyname(3C)`. `None` if the zone could not be resolved, e.g.
+    /// if it has since halted.
+    pub zoneid: Option<i32>,
     /// The zone alias. Not all zones are assigned an alias.
     pub alias: Alias,
     /// Total size of objects in memory accounted for the zone.
@@ -58,63 +71,301 @@ pub struct ZoneMemStat {
     pub swap: Swap,
 }
 
-/// Takes no input. Returns the output from `zonememstat -Ha` as async.
-async fn get_state() -> Result<Vec<ZoneMemStat>, Box<dyn std::error::Error>> {
+/// An error returned when a line of `zonememstat` output does not match the
+/// expected seven-column format.
+#[derive(Debug, PartialEq)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to parse zonememstat line: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Spawns `zonememstat -Ha` and returns a stream that yields each parsed
+/// record as its line arrives, rather than waiting for every zone and
+/// buffering the whole table in memory. `stderr` lines are skipped. Each
+/// record's `zoneid` is resolved from its `zonename` via
+/// `getzoneidbyname(3C)` as it comes off the stream.
+pub fn stat_stream() -> Pin<Box<dyn Stream<Item = Result<ZoneMemStat, ParseError>> + Send>> {
     let zms = "zonememstat";
     let args = ["-H", "-a"];
 
-    let mut result: Vec<ZoneMemStat> = Vec::new();
+    match Command::new(zms).args(&args).try_into() {
+        Ok(procstream) => {
+            let procstream: ProcessLineStream = procstream;
+            Box::pin(procstream.filter_map(|line| {
+                line.stdout().map(|l| {
+                    parse_line(l).map(|mut zms| {
+                        zms.zoneid = getzoneidbyname(&zms.zonename).ok();
+                        zms
+                    })
+                })
+            }))
+        }
+        Err(err) => {
+            eprintln!("Error executing zonememstat: {:?}", err);
+            Box::pin(tokio_stream::empty())
+        }
+    }
+}
 
-    let mut procstream: ProcessLineStream = Command::new(zms)
-        .args(&args)
-        .try_into()?;
+/// A run of non-whitespace characters, used for the `zonename` and `alias`
+/// columns.
+fn token(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| !c.is_whitespace())(input)
+}
 
-    while let Some(line) = procstream.next().await {
-        match line.stdout() {
-            Some(l) => result.push(parse_line(l)),
-            None => ()
-        };
-    }
+/// The `alias` column. `zonememstat` prints `-` for zones with no alias.
+fn alias(input: &str) -> IResult<&str, Alias> {
+    alt((
+        map(char('-'), |_| Alias::None),
+        map(token, |s: &str| Alias::String(s.to_string())),
+    ))(input)
+}
 
-    Ok(result)
+/// The `swap` column. `zonememstat` prints `-` when swap usage could not be
+/// calculated, most commonly for the global zone.
+fn swap(input: &str) -> IResult<&str, Swap> {
+    alt((
+        map(char('-'), |_| Swap::None),
+        map_res(
+            take_while1(|c: char| c.is_ascii_digit() || c == '.'),
+            |s: &str| s.parse::<f64>().map(Swap::Float),
+        ),
+    ))(input)
 }
 
-/// Parse a single line from `zonememstat`
-fn parse_line(x: &str) -> ZoneMemStat {
-    let split = x.split_whitespace();
-    let splitvec: Vec<&str> = split.collect();
+fn u64_field(input: &str) -> IResult<&str, u64> {
+    map_res(digit1, str::parse)(input)
+}
 
-    let swap = match splitvec[6].parse::<f64>() {
-        Ok(f) => Swap::Float(f),
-        Err(_) => Swap::None,
-    };
+fn u32_field(input: &str) -> IResult<&str, u32> {
+    map_res(digit1, str::parse)(input)
+}
 
-    let alias = match splitvec[1] {
-        "-" => Alias::None,
-        _ => Alias::String(splitvec[1].to_string()),
-    };
+/// A nom parser for a single `zonememstat -Ha` line, in column order:
+/// `zonename alias rss cap nover pout swap`.
+fn zonememstat_line(input: &str) -> IResult<&str, ZoneMemStat> {
+    let (input, _) = nom::character::complete::space0(input)?;
+    let (input, zonename) = token(input)?;
+    let (input, _) = space1(input)?;
+    let (input, alias) = alias(input)?;
+    let (input, _) = space1(input)?;
+    let (input, rss) = u64_field(input)?;
+    let (input, _) = space1(input)?;
+    let (input, cap) = u64_field(input)?;
+    let (input, _) = space1(input)?;
+    let (input, nover) = u32_field(input)?;
+    let (input, _) = space1(input)?;
+    let (input, pout) = u64_field(input)?;
+    let (input, _) = space1(input)?;
+    let (input, swap) = swap(input)?;
 
-    ZoneMemStat {
-        zonename: splitvec[0].to_string(),
-        alias,
-        rss: splitvec[2].parse().expect("Expected a string"),
-        cap: splitvec[3].parse().expect("Expected a string"),
-        nover: splitvec[4].parse().expect("Expected a string"),
-        pout: splitvec[5].parse().expect("Expected a string"),
-        swap,
+    Ok((
+        input,
+        ZoneMemStat {
+            zoneid: None,
+            zonename: zonename.to_string(),
+            alias,
+            rss,
+            cap,
+            nover,
+            pout,
+            swap,
+        },
+    ))
+}
+
+/// Parse a single line from `zonememstat`. This is a pure function with no
+/// syscalls: `zoneid` is always `None` here, and is resolved by the caller.
+fn parse_line(x: &str) -> Result<ZoneMemStat, ParseError> {
+    match zonememstat_line(x) {
+        Ok((rest, stat)) if rest.trim().is_empty() => Ok(stat),
+        _ => Err(ParseError(x.to_string())),
     }
 }
 
 /// Takes no input. Returns a Vec of ZoneMemStat structs. The global zone will
-/// always be element `0`.
+/// always be element `0`. This is a convenience wrapper around
+/// [`stat_stream`] for callers that want the whole table at once, and its
+/// `Vec` is lossy: lines that fail to parse are logged to stderr and
+/// dropped, with no way for the caller to tell the table is incomplete.
+/// Callers that need to observe (or fail on) a parse error should use
+/// [`stat_stream`] directly instead, which yields each line's
+/// `Result<ZoneMemStat, ParseError>`.
 pub async fn stat() -> Vec<ZoneMemStat> {
-    match get_state().await {
-        Ok(v) => v,
-        Err(err) => {
-            eprintln!("Error executing zonememstat: {:?}", err);
-            Vec::new()
+    let mut stream = stat_stream();
+    let mut result = Vec::new();
+
+    while let Some(item) = stream.next().await {
+        match item {
+            Ok(zms) => result.push(zms),
+            Err(err) => eprintln!("Error parsing zonememstat line: {:?}", err),
+        }
+    }
+
+    result
+}
+
+/// Returns the stats for a single zone, matched against either its
+/// `zonename` or its `alias`. Returns `None` if `zonememstat` has no record
+/// for `name`.
+pub async fn stat_for_zone(name: &str) -> Option<ZoneMemStat> {
+    let mut stream = stat_stream();
+
+    while let Some(item) = stream.next().await {
+        match item {
+            Ok(zms) => {
+                let matches = zms.zonename == name
+                    || matches!(&zms.alias, Alias::String(alias) if alias == name);
+                if matches {
+                    return Some(zms);
+                }
+            }
+            Err(err) => eprintln!("Error parsing zonememstat line: {:?}", err),
         }
     }
+
+    None
+}
+
+/// Returns the stats for the zone the calling process is running in,
+/// resolved via `getzoneid(2)` and `getzonenamebyid(3C)`.
+pub async fn stat_for_self() -> Option<ZoneMemStat> {
+    let id = getzoneid().ok()?;
+    let name = getzonenamebyid(id).ok()?;
+    stat_for_zone(&name).await
+}
+
+/// Reads per-zone memory statistics directly from the illumos `memory_cap`
+/// kstat module instead of spawning `zonememstat` on every poll, which
+/// matters for latency-sensitive, high-frequency monitoring. `swap` isn't
+/// available from kstat and is always [`Swap::None`]. Requires the `kstat`
+/// feature.
+///
+/// Unlike [`stat`], this has no record for the global zone: `memory_cap`
+/// has no kstat instance for it. Callers that feed this into [`summarize`]
+/// will see `gz_rss == 0` as a result; mixing this backend with [`stat`]'s
+/// output is not supported.
+#[cfg(feature = "kstat")]
+pub async fn stat_kstat() -> Result<Vec<ZoneMemStat>, Box<dyn std::error::Error>> {
+    let ctl = kstat::KstatCtl::new()?;
+    let mut result = Vec::new();
+
+    for ks in ctl.iter() {
+        if ks.module() != "memory_cap" || ks.class() != "zone_memory_cap" {
+            continue;
+        }
+
+        let data = ks.read(&ctl)?;
+        let zoneid = ks.instance();
+
+        let rss = data.get_u64("rss")? / (1024 * 1024);
+        let nover = data.get_u64("nover")? as u32;
+        let pout = data.get_u64("pagedout")? / (1024 * 1024);
+
+        // An uncapped zone reports `physcap` as `UINT64_MAX` rather than `0`;
+        // normalize it to the `0 == unlimited` convention `zonememstat`
+        // (and ZoneMemStat.cap) use.
+        let physcap = data.get_u64("physcap")?;
+        let cap = if physcap == u64::MAX {
+            0
+        } else {
+            physcap / (1024 * 1024)
+        };
+
+        let zonename = getzonenamebyid(zoneid).unwrap_or_default();
+
+        result.push(ZoneMemStat {
+            zonename,
+            zoneid: Some(zoneid),
+            // kstat has no notion of a zone's alias; that's only available
+            // from zone configuration, which this backend doesn't read.
+            alias: Alias::None,
+            rss,
+            cap,
+            nover,
+            pout,
+            swap: Swap::None,
+        });
+    }
+
+    Ok(result)
+}
+
+/// A rolled-up summary of memory usage across all zones, analogous to the
+/// whole-system `VirtualMemory` struct exposed by tools like psutil.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct MemSummary {
+    /// Sum of `rss` across all zones, including the global zone.
+    pub total_rss: u64,
+    /// Sum of `cap` across all non-global zones.
+    pub total_cap: u64,
+    /// The number of non-global zones included in the summary.
+    pub ngz_count: usize,
+    /// Sum of `nover` across all zones.
+    pub total_nover: u32,
+    /// Sum of `pout` across all zones.
+    pub total_pout: u64,
+    /// The global zone's `rss`, broken out since it isn't capped the same
+    /// way non-global zones are.
+    pub gz_rss: u64,
+    /// `zonememstat` does not calculate global-zone swap, so this estimates
+    /// it from the non-global zones: the average of each non-global zone's
+    /// `swap` percentage, weighted by its `cap`.
+    pub gz_swap: f64,
+}
+
+/// Rolls up the per-zone stats returned by [`stat`] into a single
+/// [`MemSummary`], deriving the global zone's missing swap figure from the
+/// aggregate swap pressure of the non-global zones.
+pub fn summarize(stats: &[ZoneMemStat]) -> MemSummary {
+    let mut total_rss = 0;
+    let mut total_cap = 0;
+    let mut ngz_count = 0;
+    let mut total_nover = 0;
+    let mut total_pout = 0;
+    let mut gz_rss = 0;
+    let mut swap_weighted = 0.0;
+    let mut swap_weight = 0;
+
+    for zms in stats {
+        total_rss += zms.rss;
+        total_nover += zms.nover;
+        total_pout += zms.pout;
+
+        if zms.zonename == "global" {
+            gz_rss = zms.rss;
+            continue;
+        }
+
+        total_cap += zms.cap;
+        ngz_count += 1;
+
+        if let Swap::Float(pct) = zms.swap {
+            swap_weighted += pct * zms.cap as f64;
+            swap_weight += zms.cap;
+        }
+    }
+
+    let gz_swap = if swap_weight > 0 {
+        swap_weighted / swap_weight as f64
+    } else {
+        0.0
+    };
+
+    MemSummary {
+        total_rss,
+        total_cap,
+        ngz_count,
+        total_nover,
+        total_pout,
+        gz_rss,
+        gz_swap,
+    }
 }
 
 #[cfg(test)]
@@ -123,11 +374,12 @@ mod tests {
 
     #[test]
     fn test_parse_gz() {
-        let parsed = parse_line("                               global            -      850 16777215        0         0     -");
+        let parsed = parse_line("                               global            -      850 16777215        0         0     -").unwrap();
 
         // Define the expected values
         let expected = ZoneMemStat {
             zonename: "global".to_string(),
+            zoneid: None,
             alias: Alias::None,
             rss: 850,
             cap: 16777215,
@@ -142,11 +394,12 @@ mod tests {
 
     #[test]
     fn test_parse_ngz() {
-        let parsed = parse_line(" 6dc5da73-e4e5-45b6-80b9-5d2073e9b1ee        amon0      174   1024        0         0 7.11193");
+        let parsed = parse_line(" 6dc5da73-e4e5-45b6-80b9-5d2073e9b1ee        amon0      174   1024        0         0 7.11193").unwrap();
 
         // Define the expected values
         let expected = ZoneMemStat {
             zonename: "6dc5da73-e4e5-45b6-80b9-5d2073e9b1ee".to_string(),
+            zoneid: None,
             alias: Alias::String("amon0".to_string()),
             rss: 174,
             cap: 1024,
@@ -161,11 +414,12 @@ mod tests {
 
     #[test]
     fn test_parse_ngz_no_alias() {
-        let parsed = parse_line(" 6dc5da73-e4e5-45b6-80b9-5d2073e9b1ee            -      174   1024        0         0 7.11193");
+        let parsed = parse_line(" 6dc5da73-e4e5-45b6-80b9-5d2073e9b1ee            -      174   1024        0         0 7.11193").unwrap();
 
         // Define the expected values
         let expected = ZoneMemStat {
             zonename: "6dc5da73-e4e5-45b6-80b9-5d2073e9b1ee".to_string(),
+            zoneid: None,
             alias: Alias::None,
             rss: 174,
             cap: 1024,
@@ -177,4 +431,25 @@ mod tests {
         // Compare the actual instance with the expected instance
         assert_eq!(parsed, expected);
     }
+
+    #[test]
+    fn test_summarize() {
+        let gz = parse_line("                               global            -      850 16777215        0         0     -").unwrap();
+        let ngz1 = parse_line(" 6dc5da73-e4e5-45b6-80b9-5d2073e9b1ee        amon0      174   1024        0         0 7.11193").unwrap();
+        let ngz2 = parse_line(" 7dc5da73-e4e5-45b6-80b9-5d2073e9b1ee        amon1      100   1024        1         5 0.0").unwrap();
+
+        let summary = summarize(&[gz, ngz1, ngz2]);
+
+        let expected = MemSummary {
+            total_rss: 850 + 174 + 100,
+            total_cap: 1024 + 1024,
+            ngz_count: 2,
+            total_nover: 1,
+            total_pout: 5,
+            gz_rss: 850,
+            gz_swap: (7.11193 * 1024.0 + 0.0 * 1024.0) / (1024.0 + 1024.0),
+        };
+
+        assert_eq!(summary, expected);
+    }
 }